@@ -1,12 +1,18 @@
 use anyhow::{anyhow, Context, Result};
+use flate2::read::GzDecoder;
 use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use ignore::{WalkBuilder, WalkState};
+use libarchive::archive::{Entry, ReadFilter, ReadFormat};
+use libarchive::reader::{Builder, Reader};
 use log::error;
 use rayon::prelude::*;
 use std::collections::{HashMap, HashSet};
-use std::fmt::Display;
+use std::io::{Read, Write};
 use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::fs::PermissionsExt;
+use std::sync::Mutex;
 use structopt::StructOpt;
-use walkdir::WalkDir;
 
 #[derive(StructOpt)]
 #[structopt(name = "colaz")]
@@ -19,6 +25,61 @@ struct Args {
     repo: String,
     #[structopt(long, help = "ignore dir", default_value = "/etc/archdiff/ignore")]
     ignore: String,
+    #[structopt(long, help = "package cache dir", default_value = "/var/cache/pacman/pkg")]
+    cache: String,
+    #[structopt(long, help = "output format: plain, porcelain, json", default_value = "plain")]
+    format: Format,
+    #[structopt(long, help = "report untracked (?) files")]
+    untracked: bool,
+    #[structopt(long, help = "report modified (R/P) files")]
+    modified: bool,
+    #[structopt(long, help = "report deleted (D) files")]
+    deleted: bool,
+    #[structopt(long, help = "report changed backup (B) files")]
+    backup: bool,
+    #[structopt(long, help = "collapse matching untracked/deleted pairs into renames")]
+    detect_renames: bool,
+    #[structopt(subcommand)]
+    cmd: Option<Command>,
+}
+
+enum Format {
+    Plain,
+    Porcelain,
+    Json,
+}
+
+impl std::str::FromStr for Format {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "plain" => Ok(Format::Plain),
+            "porcelain" => Ok(Format::Porcelain),
+            "json" => Ok(Format::Json),
+            other => Err(anyhow!("unknown format {}", other)),
+        }
+    }
+}
+
+#[derive(StructOpt)]
+enum Command {
+    #[structopt(about = "diff the root against installed packages (default)")]
+    Status,
+    #[structopt(about = "populate the repo dir from cached package archives")]
+    Sync,
+}
+
+// Per-worker scratch buffer for the parallel walk; its files are appended to
+// the shared sink when the worker's visitor is dropped on thread exit.
+struct Local<'a> {
+    files: Vec<String>,
+    sink: &'a Mutex<Vec<Vec<String>>>,
+}
+
+impl Drop for Local<'_> {
+    fn drop(&mut self) {
+        self.sink.lock().unwrap().push(std::mem::take(&mut self.files));
+    }
 }
 
 struct App {
@@ -43,17 +104,129 @@ fn hash_file_logged<P: AsRef<std::path::Path>>(path: P) -> Option<String> {
     }
 }
 
-fn filter_map_error<Error: Display, O>(result: std::result::Result<O, Error>) -> Option<O> {
-    match result {
-        Ok(o) => Some(o),
-        Err(err) => {
-            error!("{}", err);
-            None
+// A single per-file record resolved from a package's MTREE, with the `/set`
+// defaults already folded in.
+struct MtreeEntry {
+    path: String,
+    mode: u32,
+    uid: u32,
+    gid: u32,
+    is_link: bool,
+    link: Option<Vec<u8>>,
+}
+
+// Decode the vis(3) escaping pacman uses in MTREE paths and link targets:
+// `\\` is a literal backslash and `\ooo` is an octal byte; everything else is
+// passed through verbatim.
+fn unvis(s: &str) -> Vec<u8> {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'\\' && i + 1 < bytes.len() {
+            let next = bytes[i + 1];
+            if next == b'\\' {
+                out.push(b'\\');
+                i += 2;
+            } else if (b'0'..=b'3').contains(&next)
+                && i + 4 <= bytes.len()
+                && (b'0'..=b'7').contains(&bytes[i + 2])
+                && (b'0'..=b'7').contains(&bytes[i + 3])
+            {
+                let b = (next - b'0') * 64 + (bytes[i + 2] - b'0') * 8 + (bytes[i + 3] - b'0');
+                out.push(b);
+                i += 4;
+            } else {
+                out.push(bytes[i]);
+                i += 1;
+            }
+        } else {
+            out.push(bytes[i]);
+            i += 1;
         }
     }
+    out
 }
 
-// TODO: command to sync /usr/share/archdiff automatically
+// Parse a gzip-compressed BSD MTREE file into per-file records. The `/set`
+// line carries defaults that each subsequent path line may override; paths are
+// emitted without their leading `./` to match the package file naming.
+fn parse_mtree<P: AsRef<std::path::Path>>(path: P) -> Result<Vec<MtreeEntry>> {
+    let file = std::fs::File::open(path.as_ref())
+        .with_context(|| format!("failed to open {}", path.as_ref().display()))?;
+    let mut text = String::new();
+    GzDecoder::new(file)
+        .read_to_string(&mut text)
+        .with_context(|| format!("failed to decompress {}", path.as_ref().display()))?;
+
+    let mut mode = 0u32;
+    let mut uid = 0u32;
+    let mut gid = 0u32;
+    let mut kind = String::new();
+    let mut entries = vec![];
+
+    let apply = |key: &str, value: &str, mode: &mut u32, uid: &mut u32, gid: &mut u32, kind: &mut String| match key {
+        "mode" => {
+            if let Ok(m) = u32::from_str_radix(value, 8) {
+                *mode = m;
+            }
+        }
+        "uid" => {
+            if let Ok(u) = value.parse() {
+                *uid = u;
+            }
+        }
+        "gid" => {
+            if let Ok(g) = value.parse() {
+                *gid = g;
+            }
+        }
+        "type" => *kind = value.to_string(),
+        _ => {}
+    };
+
+    for line in text.lines() {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if let Some(rest) = line.strip_prefix("/set ") {
+            for kv in rest.split_whitespace() {
+                if let Some((k, v)) = kv.split_once('=') {
+                    apply(k, v, &mut mode, &mut uid, &mut gid, &mut kind);
+                }
+            }
+            continue;
+        }
+        if line.starts_with("/unset ") || line.starts_with('/') {
+            continue;
+        }
+
+        let mut fields = line.split_whitespace();
+        let name = match fields.next() {
+            Some(n) => n,
+            None => continue,
+        };
+        let mut e = MtreeEntry {
+            path: String::from_utf8_lossy(&unvis(name.trim_start_matches("./"))).into_owned(),
+            mode,
+            uid,
+            gid,
+            is_link: kind == "link",
+            link: None,
+        };
+        for kv in fields {
+            if let Some((k, v)) = kv.split_once('=') {
+                match k {
+                    "type" => e.is_link = v == "link",
+                    "link" => e.link = Some(unvis(v)),
+                    _ => apply(k, v, &mut e.mode, &mut e.uid, &mut e.gid, &mut kind),
+                }
+            }
+        }
+        entries.push(e);
+    }
+    Ok(entries)
+}
 
 impl App {
     #[allow(clippy::new_ret_no_self)]
@@ -85,9 +258,63 @@ impl App {
         Ok(gi_builder.build()?)
     }
 
+    // Walk `dir` across all cores with the `ignore` crate's parallel walker,
+    // returning every file's path relative to `dir`. When `apply_ignore` is set
+    // the configured matcher prunes ignored directories during descent instead
+    // of descending into them. The walker's own gitignore/hidden heuristics are
+    // disabled so it sees the whole tree. Each worker accumulates into a local
+    // buffer that is merged once on thread exit, keeping the hot path lock-free.
+    fn walk(&self, dir: &str, apply_ignore: bool) -> Vec<String> {
+        let buffers = Mutex::new(Vec::new());
+        let ignore = &self.ignore;
+        let base = std::path::Path::new(dir);
+        WalkBuilder::new(dir)
+            .standard_filters(false)
+            .build_parallel()
+            .run(|| {
+                let mut local = Local {
+                    files: Vec::new(),
+                    sink: &buffers,
+                };
+                Box::new(move |result| {
+                    let de = match result {
+                        Ok(de) => de,
+                        Err(err) => {
+                            error!("{}", err);
+                            return WalkState::Continue;
+                        }
+                    };
+                    let is_dir = de.file_type().map(|t| t.is_dir()).unwrap_or(false);
+                    if apply_ignore && !ignore.matched(de.path(), is_dir).is_none() {
+                        return if is_dir {
+                            WalkState::Skip
+                        } else {
+                            WalkState::Continue
+                        };
+                    }
+                    if !is_dir {
+                        if let Ok(rel) = de.path().strip_prefix(base) {
+                            local.files.push(rel.to_string_lossy().into_owned());
+                        }
+                    }
+                    WalkState::Continue
+                })
+            });
+        buffers.into_inner().unwrap().concat()
+    }
+
     fn run(&self) {
+        // When no category flag is given, report everything; otherwise restrict
+        // collection to just the requested ones.
+        let any = self.args.untracked || self.args.modified || self.args.deleted || self.args.backup;
+        let want_untracked = !any || self.args.untracked;
+        let want_modified = !any || self.args.modified;
+        let want_deleted = !any || self.args.deleted;
+        let want_backup = !any || self.args.backup;
+
         let mut pkg_files = HashSet::new();
         let mut pkg_backup_files = HashMap::new();
+        let mut mtree_entries = HashMap::new();
         for pkg in self.alpm.localdb().pkgs() {
             pkg_files.extend(pkg.files().files().iter().map(|f| f.name().to_string()));
             pkg_backup_files.extend(
@@ -95,99 +322,338 @@ impl App {
                     .iter()
                     .map(|b| (b.name().to_string(), b.hash().to_string())),
             );
+            if !want_modified {
+                continue;
+            }
+            let mtree = format!(
+                "{}/local/{}-{}/mtree",
+                self.args.dbpath,
+                pkg.name(),
+                pkg.version()
+            );
+            // Shared directory entries recur across many packages; keep one
+            // record per path so each is stat-checked and reported at most once.
+            match parse_mtree(&mtree) {
+                Ok(entries) => {
+                    mtree_entries.extend(entries.into_iter().map(|e| (e.path.clone(), e)))
+                }
+                Err(err) => error!("{}", err),
+            }
         }
 
         let root = &self.args.root;
         let ignored = &self.ignore;
-        let root_len = self.args.root.len();
-        let repo_len = self.args.repo.len();
 
         let mut all = vec![];
 
-        // untracked files on disk
-        WalkDir::new(&self.args.root)
-            .into_iter()
-            .filter_entry(|de| {
-                self.ignore
-                    .matched(de.path(), de.file_type().is_dir())
-                    .is_none()
-            })
-            .filter_map(filter_map_error)
-            .for_each(|de| {
-                if de.file_type().is_dir() {
-                    return;
+        // untracked files on disk; also prunes pkg_files so the `D` pass below
+        // only stats packaged files not seen on disk. The ignore matcher is
+        // applied during descent so ignored directories are pruned rather than
+        // stat'd into.
+        if want_untracked || want_deleted {
+            for path in self.walk(&self.args.root, true) {
+                if !pkg_files.remove(&path) && want_untracked {
+                    all.push(('?', path));
                 }
-                let path = &de.path().to_string_lossy()[root_len..];
-                let removed = pkg_files.remove(path);
-                if !removed {
-                    all.push(('?', path.to_string()));
+            }
+        }
+
+        // repo files that have been changed; also prunes pkg_backup_files so the
+        // `B` pass does not re-report files already tracked in the repo.
+        if want_modified || want_backup {
+            let repo_files = self.walk(&self.args.repo, false);
+            for path in &repo_files {
+                pkg_backup_files.remove(path);
+            }
+            if want_modified {
+                all.par_extend(repo_files.into_par_iter().filter_map(|path| {
+                    let repo_hash = hash_file_logged(format!("{}{}", &self.args.repo, path))?;
+                    let actual_hash = hash_file_logged(format!("{}{}", &root, path))?;
+                    if repo_hash != actual_hash {
+                        Some(('R', path))
+                    } else {
+                        None
+                    }
+                }));
+            }
+        }
+
+        // deleted files from packages
+        if want_deleted {
+            all.par_extend(pkg_files.into_par_iter().filter_map(|p| {
+                let fp = format!("{}{}", &root, &p);
+                if ignored.matched(&fp, false).is_ignore() {
+                    None
+                } else {
+                    match std::fs::metadata(&fp).with_context(|| format!("failed to stat {}", fp)) {
+                        Err(_) => Some(('D', p)),
+                        Ok(_) => None,
+                    }
                 }
-            });
+            }));
+        }
 
-        // repo files that have been changed
-        WalkDir::new(&self.args.repo)
-            .into_iter()
-            .filter_map(filter_map_error)
-            .for_each(|de| {
-                if de.file_type().is_dir() {
-                    return;
+        // backup files that have been changed
+        if want_backup {
+            all.par_extend(
+                pkg_backup_files
+                    .into_par_iter()
+                    .filter_map(|(p, expected_hash)| {
+                        let fp = format!("{}{}", &root, &p);
+                        if ignored.matched_path_or_any_parents(&fp, false).is_ignore() {
+                            None
+                        } else {
+                            hash_file_logged(&fp).and_then(|actual_hash| {
+                                if expected_hash == actual_hash {
+                                    None
+                                } else {
+                                    Some(('B', p))
+                                }
+                            })
+                        }
+                    }),
+            );
+        }
+
+        // metadata drift (mode/ownership/symlink target) from each package MTREE
+        if want_modified {
+            let mtree_entries: Vec<MtreeEntry> = mtree_entries.into_values().collect();
+            all.par_extend(mtree_entries.into_par_iter().filter_map(|e| {
+                let fp = format!("{}{}", &root, &e.path);
+                if ignored.matched_path_or_any_parents(&fp, false).is_ignore() {
+                    return None;
                 }
-                let path = &de.path().to_string_lossy()[repo_len..];
-                pkg_backup_files.remove(path);
-                let repo_hash = match hash_file_logged(de.path()) {
-                    None => return,
-                    Some(h) => h,
-                };
-                let actual_hash = match hash_file_logged(&format!("{}{}", &root, path)) {
-                    None => return,
-                    Some(h) => h,
-                };
-                if repo_hash != actual_hash {
-                    all.push(('R', path.to_string()));
+                // Missing files are left to the `D` pass rather than double-reported.
+                let meta = std::fs::symlink_metadata(&fp).ok()?;
+                if e.is_link {
+                    // A package symlink replaced by a regular file/dir is drift too.
+                    if !meta.file_type().is_symlink() {
+                        return Some(('P', e.path));
+                    }
+                    if let Some(expected) = &e.link {
+                        match std::fs::read_link(&fp) {
+                            Ok(target) if target.as_os_str().as_bytes() == expected.as_slice() => {}
+                            Ok(_) => return Some(('P', e.path)),
+                            Err(_) => return None,
+                        }
+                    }
+                    return None;
                 }
-            });
+                if meta.mode() & 0o7777 != e.mode || meta.uid() != e.uid || meta.gid() != e.gid {
+                    Some(('P', e.path))
+                } else {
+                    None
+                }
+            }));
+        }
 
-        // deleted files from packages
-        all.par_extend(pkg_files.into_par_iter().filter_map(|p| {
-            let fp = format!("{}{}", &root, &p);
-            if ignored.matched(&fp, false).is_ignore() {
-                None
+        if self.args.detect_renames {
+            self.detect_renames(&mut all);
+        }
+
+        all.sort_by(|(_, a), (_, b)| a.cmp(b));
+        self.print(&all);
+    }
+
+    // Correlate untracked (`?`) and deleted (`D`) entries by content: a deleted
+    // path and an untracked path with the same size and hash are collapsed into
+    // a single `M old -> new` entry and dropped from both lists. Candidates are
+    // bucketed by size first so only same-size files are ever hashed, and the
+    // hashing runs under rayon like the other passes.
+    fn detect_renames(&self, all: &mut Vec<(char, String)>) {
+        let root = &self.args.root;
+        let repo = &self.args.repo;
+
+        // An untracked file's content is read from disk; a deleted file no
+        // longer exists there, so its former content comes from the pristine
+        // copy in the repo dir (the same baseline the `R`/`B` passes diff).
+        let source = |c: char, p: &str| {
+            if c == 'D' {
+                format!("{}{}", repo, p)
             } else {
-                match std::fs::metadata(&fp).with_context(|| format!("failed to stat {}", fp)) {
-                    Err(_) => Some(('D', p)),
-                    Ok(_) => None,
-                }
+                format!("{}{}", root, p)
             }
-        }));
+        };
 
-        // backup files that have been changed
-        all.par_extend(
-            pkg_backup_files
-                .into_par_iter()
-                .filter_map(|(p, expected_hash)| {
-                    let fp = format!("{}{}", &root, &p);
-                    if ignored.matched_path_or_any_parents(&fp, false).is_ignore() {
-                        None
+        let mut by_size: HashMap<u64, (Vec<String>, Vec<String>)> = HashMap::new();
+        let mut rest = vec![];
+        for (c, p) in all.drain(..) {
+            if c != '?' && c != 'D' {
+                rest.push((c, p));
+                continue;
+            }
+            match std::fs::metadata(source(c, &p)) {
+                Ok(meta) => {
+                    let bucket = by_size.entry(meta.len()).or_default();
+                    if c == '?' {
+                        bucket.0.push(p);
                     } else {
-                        hash_file_logged(&fp).and_then(|actual_hash| {
-                            if expected_hash == actual_hash {
-                                None
-                            } else {
-                                Some(('B', p))
-                            }
-                        })
+                        bucket.1.push(p);
                     }
-                }),
-        );
+                }
+                // Candidates whose content is unreadable cannot be matched;
+                // leave them under their original category.
+                Err(_) => rest.push((c, p)),
+            }
+        }
 
-        all.sort_by(|(_, a), (_, b)| a.cmp(b));
-        all.iter()
-            .for_each(|(c, n)| println!("{} {}{}", c, &root, n));
+        for (_, (mut untracked, mut deleted)) in by_size {
+            if untracked.is_empty() || deleted.is_empty() {
+                rest.extend(untracked.into_iter().map(|p| ('?', p)));
+                rest.extend(deleted.into_iter().map(|p| ('D', p)));
+                continue;
+            }
+            untracked.sort();
+            deleted.sort();
+
+            let uhashes: HashMap<&String, Option<String>> = untracked
+                .par_iter()
+                .map(|p| (p, hash_file_logged(format!("{}{}", root, p))))
+                .collect();
+            let dhashes: HashMap<&String, Option<String>> = deleted
+                .par_iter()
+                .map(|p| (p, hash_file_logged(format!("{}{}", repo, p))))
+                .collect();
+
+            let mut used = HashSet::new();
+            for d in &deleted {
+                let dhash = match &dhashes[d] {
+                    Some(h) => h,
+                    None => {
+                        rest.push(('D', d.clone()));
+                        continue;
+                    }
+                };
+                // Smallest unused untracked path sharing this hash wins.
+                let matched = untracked
+                    .iter()
+                    .find(|u| !used.contains(*u) && uhashes[*u].as_deref() == Some(dhash));
+                if let Some(u) = matched {
+                    rest.push(('M', format!("{} -> {}", d, u)));
+                    used.insert(u.clone());
+                } else {
+                    rest.push(('D', d.clone()));
+                }
+            }
+            rest.extend(
+                untracked
+                    .into_iter()
+                    .filter(|u| !used.contains(u))
+                    .map(|p| ('?', p)),
+            );
+        }
+
+        *all = rest;
+    }
+
+    // Render the collected entries in the requested output format. Rename (`M`)
+    // entries hold a relative `old -> new` pair, so the root prefix is applied
+    // to each side; all other categories carry a single relative path.
+    fn print(&self, all: &[(char, String)]) {
+        let root = &self.args.root;
+        let path = |c: char, n: &str| match (c, n.split_once(" -> ")) {
+            ('M', Some((old, new))) => format!("{}{} -> {}{}", root, old, root, new),
+            _ => format!("{}{}", root, n),
+        };
+        match self.args.format {
+            Format::Plain => all
+                .iter()
+                .for_each(|(c, n)| println!("{} {}", c, path(*c, n))),
+            Format::Porcelain => all
+                .iter()
+                .for_each(|(c, n)| println!("{}\t{}", c, path(*c, n))),
+            Format::Json => {
+                let entries: Vec<_> = all
+                    .iter()
+                    .map(|(c, n)| {
+                        serde_json::json!({"status": c.to_string(), "path": path(*c, n)})
+                    })
+                    .collect();
+                match serde_json::to_string(&entries) {
+                    Ok(s) => println!("{}", s),
+                    Err(err) => error!("{}", err),
+                }
+            }
+        }
+    }
+
+    // Rebuild the repo dir from each installed package's cached archive by
+    // extracting only its backup files, giving the `B`/`R` passes a pristine
+    // baseline to diff against.
+    fn sync(&self) -> Result<()> {
+        for pkg in self.alpm.localdb().pkgs() {
+            let backups: HashSet<String> =
+                pkg.backup().iter().map(|b| b.name().to_string()).collect();
+            if backups.is_empty() {
+                continue;
+            }
+            if let Err(err) = self.sync_pkg(pkg.name(), &pkg.version().to_string(), pkg.arch(), &backups)
+            {
+                error!("{}", err);
+            }
+        }
+        Ok(())
+    }
+
+    fn sync_pkg(
+        &self,
+        name: &str,
+        version: &str,
+        arch: Option<&str>,
+        backups: &HashSet<String>,
+    ) -> Result<()> {
+        let arch = arch.unwrap_or("any");
+        let base = format!("{}/{}-{}-{}.pkg.tar", self.args.cache, name, version, arch);
+        let archive = [format!("{}.zst", base), format!("{}.xz", base)]
+            .into_iter()
+            .find(|p| std::path::Path::new(p).exists());
+        let archive = match archive {
+            Some(a) => a,
+            None => {
+                error!("no cached archive for {}-{}; skipping", name, version);
+                return Ok(());
+            }
+        };
+
+        let mut builder = Builder::new();
+        builder.support_format(ReadFormat::All)?;
+        builder.support_filter(ReadFilter::All)?;
+        let mut reader = builder.open_file(&archive)?;
+
+        loop {
+            let (entry_path, mode) = match reader.next_header() {
+                Some(entry) => (entry.pathname().to_string(), entry.mode()),
+                None => break,
+            };
+            let rel = entry_path.trim_start_matches("./");
+            if !backups.contains(rel) {
+                continue;
+            }
+            let dest = format!("{}{}", self.args.repo, rel);
+            if let Some(parent) = std::path::Path::new(&dest).parent() {
+                std::fs::create_dir_all(parent)
+                    .with_context(|| format!("failed to create {}", parent.display()))?;
+            }
+            let mut out = std::fs::File::create(&dest)
+                .with_context(|| format!("failed to create {}", dest))?;
+            while let Some(block) = reader.read_block()? {
+                out.write_all(block)
+                    .with_context(|| format!("failed to write {}", dest))?;
+            }
+            std::fs::set_permissions(&dest, std::fs::Permissions::from_mode(mode & 0o7777))
+                .with_context(|| format!("failed to chmod {}", dest))?;
+        }
+        Ok(())
     }
 }
 
 fn main() -> Result<()> {
     pretty_env_logger::init();
-    App::new(Args::from_args())?.run();
+    let args = Args::from_args();
+    let app = App::new(args)?;
+    match app.args.cmd {
+        Some(Command::Sync) => app.sync()?,
+        Some(Command::Status) | None => app.run(),
+    }
     Ok(())
 }